@@ -0,0 +1,198 @@
+use anyhow::Result;
+use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoClient};
+use aws_sdk_s3::Client as S3Client;
+use arrow::array::{Int64Array, StringArray};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use lambda_http::{Body, Error, Request, Response};
+use parquet::arrow::ArrowWriter;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::segments;
+use crate::storage::{ObjectStore, OffsetStore};
+
+/// Upper bound on how many records a single compaction run will pull into
+/// one Parquet file.
+const COMPACTION_BATCH_LIMIT: i32 = 10_000;
+
+#[derive(Deserialize)]
+struct CompactRecord {
+    offset: i64,
+    payload: serde_json::Value,
+    timestamp: u128,
+}
+
+#[derive(Serialize)]
+struct CompactResponse {
+    topic: String,
+    compacted: bool,
+    base_offset: Option<i64>,
+    last_offset: Option<i64>,
+    records: usize,
+    key: Option<String>,
+}
+
+/// Handle POST /topics/{topic}/compact
+///
+/// Rolls the next uncompacted range of a topic's history into a columnar
+/// Parquet file under `topics/{topic}/compacted/`, so analytical tools can
+/// scan history without replaying JSON segments. Safe to call repeatedly or
+/// on a schedule: idempotent because the next range always starts right
+/// after the last offset recorded in the compacted-range index, so a rerun
+/// with nothing new to compact is a no-op.
+pub async fn handle(
+    _event: Request,
+    s3: &S3Client,
+    dynamo: &DynamoClient,
+    object_store: &Arc<dyn ObjectStore>,
+    offset_store: &Arc<dyn OffsetStore>,
+    bucket: &str,
+    segment_index_table: &str,
+    topic: &str,
+) -> Result<Response<Body>, Error> {
+    let start_offset = next_compaction_start(dynamo, segment_index_table, topic).await?;
+    let current_offset = offset_store.read(topic).await?;
+
+    if current_offset < start_offset {
+        tracing::info!(%topic, %start_offset, %current_offset, "Nothing new to compact");
+        return ok_response(CompactResponse {
+            topic: topic.to_string(),
+            compacted: false,
+            base_offset: None,
+            last_offset: None,
+            records: 0,
+            key: None,
+        });
+    }
+
+    let records =
+        segments::read_range(s3, dynamo, segment_index_table, bucket, topic, start_offset, COMPACTION_BATCH_LIMIT)
+            .await?;
+
+    if records.is_empty() {
+        tracing::info!(%topic, %start_offset, "No sealed records available to compact yet");
+        return ok_response(CompactResponse {
+            topic: topic.to_string(),
+            compacted: false,
+            base_offset: None,
+            last_offset: None,
+            records: 0,
+            key: None,
+        });
+    }
+
+    let batch = build_record_batch(&records)?;
+    let last_offset = records.last().map(|(offset, _)| *offset).unwrap();
+
+    let mut buffer = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut buffer, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    let key = format!("topics/{}/compacted/{:020}.parquet", topic, start_offset);
+    object_store.put(&key, buffer).await?;
+
+    record_compacted_range(dynamo, segment_index_table, topic, start_offset, last_offset).await?;
+
+    tracing::info!(%topic, %start_offset, %last_offset, count = records.len(), %key, "Compacted records to Parquet");
+
+    ok_response(CompactResponse {
+        topic: topic.to_string(),
+        compacted: true,
+        base_offset: Some(start_offset),
+        last_offset: Some(last_offset),
+        records: records.len(),
+        key: Some(key),
+    })
+}
+
+fn ok_response(response: CompactResponse) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+/// Transpose the message records into a three-column Arrow batch: `offset`,
+/// `timestamp`, and `payload` (flattened to its JSON text representation,
+/// since payload schemas are caller-defined and not known ahead of time).
+fn build_record_batch(records: &[(i64, Vec<u8>)]) -> Result<RecordBatch, Error> {
+    let parsed: Vec<CompactRecord> = records
+        .iter()
+        .filter_map(|(_, bytes)| serde_json::from_slice::<CompactRecord>(bytes).ok())
+        .collect();
+
+    let offsets = Int64Array::from_iter_values(parsed.iter().map(|r| r.offset));
+    let timestamps = Int64Array::from_iter_values(parsed.iter().map(|r| r.timestamp as i64));
+    let payloads: StringArray = parsed
+        .iter()
+        .map(|r| serde_json::to_string(&r.payload).unwrap_or_default())
+        .collect();
+
+    let schema = Schema::new(vec![
+        Field::new("offset", DataType::Int64, false),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("payload", DataType::Utf8, false),
+    ]);
+
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(offsets), Arc::new(timestamps), Arc::new(payloads)],
+    )?)
+}
+
+/// Find the offset right after the end of the most recently compacted
+/// range, so a fresh compaction run picks up where the last one left off.
+/// Defaults to 1 (the start of the topic) if nothing has been compacted.
+async fn next_compaction_start(client: &DynamoClient, index_table: &str, topic: &str) -> Result<i64, Error> {
+    let result = client
+        .query()
+        .table_name(index_table)
+        .key_condition_expression("pk = :pk")
+        .expression_attribute_values(":pk", AttributeValue::S(format!("{}#compacted", topic)))
+        .scan_index_forward(false)
+        .limit(1)
+        .send()
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, %topic, "Failed to resolve last compacted range");
+            e
+        })?;
+
+    let last_offset = result
+        .items()
+        .first()
+        .and_then(|item| item.get("last_offset"))
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse::<i64>().ok());
+
+    Ok(last_offset.map(|o| o + 1).unwrap_or(1))
+}
+
+/// Record that `[base_offset, last_offset]` has been compacted, keyed
+/// `pk = topic#compacted`, `sk = base_offset`, so later runs can find where
+/// to resume and skip spans that are already covered.
+async fn record_compacted_range(
+    client: &DynamoClient,
+    index_table: &str,
+    topic: &str,
+    base_offset: i64,
+    last_offset: i64,
+) -> Result<(), Error> {
+    client
+        .update_item()
+        .table_name(index_table)
+        .key("pk", AttributeValue::S(format!("{}#compacted", topic)))
+        .key("sk", AttributeValue::N(base_offset.to_string()))
+        .update_expression("SET last_offset = :last")
+        .expression_attribute_values(":last", AttributeValue::N(last_offset.to_string()))
+        .send()
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, %topic, %base_offset, %last_offset, "Failed to record compacted range");
+            e
+        })?;
+
+    Ok(())
+}
@@ -1,14 +1,28 @@
 use anyhow::Result;
-use aws_sdk_s3::Client as S3Client;
-use futures::stream::{self, StreamExt};
+use aws_sdk_dynamodb::Client as DynamoClient;
+use aws_sdk_s3::{presigning::PresigningConfig, Client as S3Client};
 use lambda_http::{Body, Error, Request, RequestExt, Response};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
-const MAX_CONCURRENT_READS: usize = 10;
+use crate::segments;
+use crate::storage::OffsetStore;
+
+/// Upper bound on how long a long-poll can block, to stay comfortably under
+/// the API Gateway / Lambda integration timeout.
+const MAX_WAIT_MS: u64 = 25_000;
+
+/// Interval between `current_offset` polls while long-polling for new data.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a presigned consume URL stays valid.
+const PRESIGNED_URL_TTL: Duration = Duration::from_secs(900);
 
 #[derive(Serialize, Deserialize)]
-struct Message {
-    offset: i64,
+pub(crate) struct Message {
+    pub(crate) offset: i64,
     payload: serde_json::Value,
     timestamp: u128,
 }
@@ -19,11 +33,28 @@ struct ConsumeResponse {
     next_offset: i64,
 }
 
-/// Handle GET /topics/{topic}/consume?offset=1&limit=10
+#[derive(Serialize)]
+struct SegmentUrl {
+    base_offset: i64,
+    last_offset: i64,
+    download_url: String,
+}
+
+#[derive(Serialize)]
+struct ConsumeUrlResponse {
+    segments: Vec<SegmentUrl>,
+    next_offset: i64,
+    expires_in_seconds: u64,
+}
+
+/// Handle GET /topics/{topic}/consume?offset=1&limit=10&wait=20000
 pub async fn handle(
     event: Request,
     s3: &S3Client,
+    dynamo: &DynamoClient,
+    offset_store: &Arc<dyn OffsetStore>,
     bucket: &str,
+    segment_index_table: &str,
     topic: &str,
 ) -> Result<Response<Body>, Error> {
     // Parse query parameters
@@ -37,61 +68,40 @@ pub async fn handle(
         .and_then(|s: &str| s.parse().ok())
         .unwrap_or(10)
         .min(100); // Cap at 100
-
-    tracing::info!(%topic, %start_offset, %limit, "Consuming messages");
-
-    // List objects from S3
-    let prefix = format!("topics/{}/", topic);
-    let start_after = if start_offset > 1 {
-        format!("topics/{}/{:020}.json", topic, start_offset - 1)
-    } else {
-        String::new()
-    };
-
-    let mut list_req = s3.list_objects_v2().bucket(bucket).prefix(&prefix).max_keys(limit);
-
-    if !start_after.is_empty() {
-        list_req = list_req.start_after(&start_after);
-    }
-
-    let list_result = list_req.send().await.map_err(|e| {
-        tracing::error!(error = %e, %prefix, "Failed to list S3 objects");
-        e
-    })?;
-
-    let keys: Vec<String> = list_result
-        .contents()
-        .iter()
-        .filter_map(|obj| obj.key().map(String::from))
-        .collect();
-
-    if keys.is_empty() {
-        let response = ConsumeResponse {
-            messages: vec![],
-            next_offset: start_offset,
-        };
-        return Ok(Response::builder()
-            .status(200)
-            .header("content-type", "application/json")
-            .body(Body::from(serde_json::to_string(&response)?))?);
+    let wait_ms: u64 = params
+        .first("wait")
+        .and_then(|s: &str| s.parse().ok())
+        .unwrap_or(0)
+        .min(MAX_WAIT_MS);
+
+    tracing::info!(%topic, %start_offset, %limit, %wait_ms, "Consuming messages");
+
+    let mut messages =
+        list_and_fetch(s3, dynamo, segment_index_table, bucket, topic, start_offset, limit).await?;
+
+    // Long-poll: if there's nothing to return yet, wait for the topic's
+    // current_offset to reach start_offset instead of making the
+    // client busy-loop, then do the real fetch once data exists.
+    if messages.is_empty() && wait_ms > 0 {
+        let deadline = Instant::now() + Duration::from_millis(wait_ms);
+
+        loop {
+            if Instant::now() >= deadline {
+                break;
+            }
+
+            tokio::time::sleep(POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now()))).await;
+
+            let current_offset = offset_store.read(topic).await?;
+            if current_offset >= start_offset {
+                messages =
+                    list_and_fetch(s3, dynamo, segment_index_table, bucket, topic, start_offset, limit)
+                        .await?;
+                break;
+            }
+        }
     }
 
-    // Fetch messages in parallel with bounded concurrency
-    let messages: Vec<Message> = stream::iter(keys)
-        .map(|key| {
-            let s3 = s3.clone();
-            let bucket = bucket.to_string();
-            async move { fetch_message(&s3, &bucket, &key).await }
-        })
-        .buffer_unordered(MAX_CONCURRENT_READS)
-        .filter_map(|result| async { result.ok() })
-        .collect()
-        .await;
-
-    // Sort by offset (buffer_unordered doesn't preserve order)
-    let mut messages = messages;
-    messages.sort_by_key(|m| m.offset);
-
     let next_offset = messages.last().map(|m| m.offset + 1).unwrap_or(start_offset);
 
     tracing::info!(%topic, count = messages.len(), %next_offset, "Messages consumed");
@@ -107,12 +117,93 @@ pub async fn handle(
         .body(Body::from(serde_json::to_string(&response)?))?)
 }
 
-/// Fetch a single message from S3
-async fn fetch_message(s3: &S3Client, bucket: &str, key: &str) -> Result<Message, Error> {
-    let result = s3.get_object().bucket(bucket).key(key).send().await?;
+/// Handle GET /topics/{topic}/consume-urls?offset=1&limit=10
+///
+/// Returns presigned S3 `get_object` URLs for the segment(s) covering the
+/// requested offset range, instead of inlining message bodies, so large
+/// segments can be downloaded directly from S3 without passing back through
+/// the Lambda. Since a segment holds many messages, `limit` here bounds the
+/// number of segments returned, not the number of messages — callers decode
+/// the `[offset][len][bytes]`-framed records client-side the same way
+/// `segments::read_range` does.
+pub async fn handle_urls(
+    event: Request,
+    s3: &S3Client,
+    dynamo: &DynamoClient,
+    bucket: &str,
+    segment_index_table: &str,
+    topic: &str,
+) -> Result<Response<Body>, Error> {
+    let params = event.query_string_parameters();
+    let start_offset: i64 = params
+        .first("offset")
+        .and_then(|s: &str| s.parse().ok())
+        .unwrap_or(1);
+    let limit: i32 = params
+        .first("limit")
+        .and_then(|s: &str| s.parse().ok())
+        .unwrap_or(10)
+        .min(100);
+
+    tracing::info!(%topic, %start_offset, %limit, "Issuing presigned consume URLs");
+
+    let segment_range = segments::resolve_segments_in_range(dynamo, segment_index_table, topic, start_offset, limit)
+        .await?;
+
+    let mut segment_urls = Vec::with_capacity(segment_range.len());
+    for (base_offset, last_offset) in &segment_range {
+        let key = segments::segment_key(topic, *base_offset);
+        let presigned = s3
+            .get_object()
+            .bucket(bucket)
+            .key(&key)
+            .presigned(PresigningConfig::expires_in(PRESIGNED_URL_TTL)?)
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, %key, "Failed to presign consume URL");
+                e
+            })?;
+
+        segment_urls.push(SegmentUrl {
+            base_offset: *base_offset,
+            last_offset: *last_offset,
+            download_url: presigned.uri().to_string(),
+        });
+    }
+
+    let next_offset = segment_range.last().map(|(_, last)| last + 1).unwrap_or(start_offset);
+
+    let response = ConsumeUrlResponse {
+        segments: segment_urls,
+        next_offset,
+        expires_in_seconds: PRESIGNED_URL_TTL.as_secs(),
+    };
 
-    let bytes = result.body.collect().await?.into_bytes();
-    let message: Message = serde_json::from_slice(&bytes)?;
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+/// List and fetch the message batch starting at `start_offset`, sorted by
+/// offset. Returns an empty vec if nothing is available yet.
+pub(crate) async fn list_and_fetch(
+    s3: &S3Client,
+    dynamo: &DynamoClient,
+    segment_index_table: &str,
+    bucket: &str,
+    topic: &str,
+    start_offset: i64,
+    limit: i32,
+) -> Result<Vec<Message>, Error> {
+    let records =
+        segments::read_range(s3, dynamo, segment_index_table, bucket, topic, start_offset, limit).await?;
+
+    let mut messages: Vec<Message> = records
+        .into_iter()
+        .filter_map(|(_, bytes)| serde_json::from_slice::<Message>(&bytes).ok())
+        .collect();
+    messages.sort_by_key(|m| m.offset);
 
-    Ok(message)
+    Ok(messages)
 }
@@ -0,0 +1,202 @@
+use anyhow::Result;
+use aws_sdk_dynamodb::{types::AttributeValue, types::ReturnValue, Client as DynamoClient};
+use aws_sdk_s3::Client as S3Client;
+use lambda_http::{Body, Error, Request, RequestExt, RequestPayloadExt, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::consume;
+
+#[derive(Deserialize)]
+struct CommitRequest {
+    committed_offset: i64,
+}
+
+#[derive(Serialize)]
+struct CommitResponse {
+    topic: String,
+    group: String,
+    committed_offset: i64,
+}
+
+#[derive(Serialize)]
+struct GroupConsumeResponse {
+    topic: String,
+    group: String,
+    messages: Vec<consume::Message>,
+    next_offset: i64,
+    committed_offset: i64,
+}
+
+/// Handle POST /topics/{topic}/groups/{group}/commit
+pub async fn handle_commit(
+    event: Request,
+    dynamo: &DynamoClient,
+    table: &str,
+    topic: &str,
+    group: &str,
+) -> Result<Response<Body>, Error> {
+    let body: CommitRequest = match event.payload() {
+        Ok(Some(b)) => b,
+        Ok(None) => {
+            return Ok(Response::builder()
+                .status(400)
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"error":"missing request body"}"#))?)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to parse request body");
+            return Ok(Response::builder()
+                .status(400)
+                .header("content-type", "application/json")
+                .body(Body::from(format!(r#"{{"error":"invalid json: {}"}}"#, e)))?)
+        }
+    };
+
+    let committed_offset = commit_offset(dynamo, table, topic, group, body.committed_offset).await?;
+
+    tracing::info!(%topic, %group, %committed_offset, "Committed consumer group offset");
+
+    let response = CommitResponse {
+        topic: topic.to_string(),
+        group: group.to_string(),
+        committed_offset,
+    };
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+/// Handle GET /topics/{topic}/groups/{group}/consume?limit=10&autocommit=true
+pub async fn handle_consume(
+    event: Request,
+    s3: &S3Client,
+    dynamo: &DynamoClient,
+    bucket: &str,
+    table: &str,
+    segment_index_table: &str,
+    topic: &str,
+    group: &str,
+) -> Result<Response<Body>, Error> {
+    let params = event.query_string_parameters();
+    let limit: i32 = params
+        .first("limit")
+        .and_then(|s: &str| s.parse().ok())
+        .unwrap_or(10)
+        .min(100);
+    let autocommit: bool = params
+        .first("autocommit")
+        .and_then(|s: &str| s.parse().ok())
+        .unwrap_or(false);
+
+    let committed_offset = get_committed_offset(dynamo, table, topic, group).await?;
+
+    tracing::info!(%topic, %group, %committed_offset, %limit, "Consuming as group");
+
+    let messages =
+        consume::list_and_fetch(s3, dynamo, segment_index_table, bucket, topic, committed_offset, limit)
+            .await?;
+    let next_offset = messages.last().map(|m| m.offset + 1).unwrap_or(committed_offset);
+
+    let mut new_committed_offset = committed_offset;
+    if autocommit && next_offset > committed_offset {
+        new_committed_offset = commit_offset(dynamo, table, topic, group, next_offset).await?;
+    }
+
+    tracing::info!(%topic, %group, count = messages.len(), %next_offset, "Group messages consumed");
+
+    let response = GroupConsumeResponse {
+        topic: topic.to_string(),
+        group: group.to_string(),
+        messages,
+        next_offset,
+        committed_offset: new_committed_offset,
+    };
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+/// Read a consumer group's committed offset from DynamoDB, keyed
+/// `pk = topic#group`. Defaults to 1 (the start of the topic) if the group
+/// has never committed.
+async fn get_committed_offset(
+    client: &DynamoClient,
+    table: &str,
+    topic: &str,
+    group: &str,
+) -> Result<i64, Error> {
+    let pk = format!("{}#{}", topic, group);
+
+    let result = client
+        .get_item()
+        .table_name(table)
+        .key("pk", AttributeValue::S(pk))
+        .send()
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, %topic, %group, "Failed to read committed offset from DynamoDB");
+            e
+        })?;
+
+    let offset = result
+        .item()
+        .and_then(|item| item.get("committed_offset"))
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1);
+
+    Ok(offset)
+}
+
+/// Atomically advance a consumer group's committed offset, guarding against
+/// going backwards. If `new_offset` is not greater than the currently
+/// committed offset the conditional update is a no-op and the existing
+/// value is returned instead.
+async fn commit_offset(
+    client: &DynamoClient,
+    table: &str,
+    topic: &str,
+    group: &str,
+    new_offset: i64,
+) -> Result<i64, Error> {
+    let pk = format!("{}#{}", topic, group);
+
+    let result = client
+        .update_item()
+        .table_name(table)
+        .key("pk", AttributeValue::S(pk.clone()))
+        .update_expression("SET committed_offset = :new")
+        .condition_expression("attribute_not_exists(committed_offset) OR committed_offset < :new")
+        .expression_attribute_values(":new", AttributeValue::N(new_offset.to_string()))
+        .return_values(ReturnValue::UpdatedNew)
+        .send()
+        .await;
+
+    match result {
+        Ok(output) => {
+            let offset = output
+                .attributes()
+                .and_then(|attrs| attrs.get("committed_offset"))
+                .and_then(|v| v.as_n().ok())
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(new_offset);
+            Ok(offset)
+        }
+        Err(e) => {
+            if e.as_service_error()
+                .map(|se| se.is_conditional_check_failed_exception())
+                .unwrap_or(false)
+            {
+                tracing::info!(%topic, %group, %new_offset, "Commit would move offset backwards, ignoring");
+                get_committed_offset(client, table, topic, group).await
+            } else {
+                tracing::error!(error = %e, %topic, %group, %new_offset, "Failed to commit consumer group offset");
+                Err(e.into())
+            }
+        }
+    }
+}
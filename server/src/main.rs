@@ -5,15 +5,33 @@ use lambda_http::{http::Method, run, service_fn, Body, Error, Request, Response}
 use std::sync::Arc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod compact;
 mod consume;
+mod groups;
 mod produce;
+mod segments;
+mod storage;
 
-/// Shared state across Lambda invocations (connection pooling)
+use storage::{ObjectStore, OffsetStore};
+
+/// Shared state across Lambda invocations (connection pooling).
+///
+/// `s3`/`dynamo` and `object_store`/`offset_store` intentionally coexist:
+/// `segments`/`groups` need the raw clients for ETag-conditional S3 puts
+/// and DynamoDB conditional updates/queries the storage traits don't model,
+/// while offset allocation, the produce-url staging objects and compaction
+/// output go through the traits, so those pieces can use
+/// `LocalObjectStore`/`LocalOffsetStore` instead of live AWS. See the scope
+/// note on `ObjectStore` in `storage.rs`.
 struct AppState {
     s3: S3Client,
     dynamo: DynamoClient,
     bucket: String,
     table: String,
+    dedup_table: String,
+    segment_index_table: String,
+    object_store: Arc<dyn ObjectStore>,
+    offset_store: Arc<dyn OffsetStore>,
 }
 
 #[tokio::main]
@@ -27,12 +45,22 @@ async fn main() -> Result<(), Error> {
     // Load AWS config (uses env vars or IAM role)
     let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
 
+    let s3 = S3Client::new(&config);
+    let dynamo = DynamoClient::new(&config);
+    let bucket = std::env::var("BUCKET_NAME").expect("BUCKET_NAME must be set");
+    let table = std::env::var("COUNTERS_TABLE").expect("COUNTERS_TABLE must be set");
+    let (object_store, offset_store) = storage::build_backends(s3.clone(), dynamo.clone(), &bucket, &table);
+
     // Create shared state (reused across warm invocations)
     let state = Arc::new(AppState {
-        s3: S3Client::new(&config),
-        dynamo: DynamoClient::new(&config),
-        bucket: std::env::var("BUCKET_NAME").expect("BUCKET_NAME must be set"),
-        table: std::env::var("COUNTERS_TABLE").expect("COUNTERS_TABLE must be set"),
+        s3,
+        dynamo,
+        bucket,
+        table,
+        dedup_table: std::env::var("DEDUP_TABLE").expect("DEDUP_TABLE must be set"),
+        segment_index_table: std::env::var("SEGMENT_INDEX_TABLE").expect("SEGMENT_INDEX_TABLE must be set"),
+        object_store,
+        offset_store,
     });
 
     tracing::info!(
@@ -67,14 +95,119 @@ async fn router(event: Request, state: Arc<AppState>) -> Result<Response<Body>,
         // POST /topics/{topic}/produce
         (Method::POST, ["topics", topic, "produce"]) => {
             let topic = topic.to_string();
-            produce::handle(event, &state.s3, &state.dynamo, &state.bucket, &state.table, &topic)
-                .await
+            produce::handle(
+                event,
+                &state.s3,
+                &state.dynamo,
+                &state.offset_store,
+                &state.bucket,
+                &state.table,
+                &state.segment_index_table,
+                &state.dedup_table,
+                &topic,
+            )
+            .await
+        }
+
+        // POST /topics/{topic}/produce-batch
+        (Method::POST, ["topics", topic, "produce-batch"]) => {
+            let topic = topic.to_string();
+            produce::handle_batch(
+                event,
+                &state.s3,
+                &state.dynamo,
+                &state.offset_store,
+                &state.bucket,
+                &state.table,
+                &state.segment_index_table,
+                &topic,
+            )
+            .await
+        }
+
+        // POST /topics/{topic}/produce-url
+        (Method::POST, ["topics", topic, "produce-url"]) => {
+            let topic = topic.to_string();
+            produce::handle_produce_url(&state.s3, &state.bucket, &topic).await
+        }
+
+        // POST /topics/{topic}/produce-url/commit
+        (Method::POST, ["topics", topic, "produce-url", "commit"]) => {
+            let topic = topic.to_string();
+            produce::handle_commit_produce_url(
+                event,
+                &state.s3,
+                &state.dynamo,
+                &state.object_store,
+                &state.offset_store,
+                &state.bucket,
+                &state.table,
+                &state.segment_index_table,
+                &topic,
+            )
+            .await
         }
 
         // GET /topics/{topic}/consume
         (Method::GET, ["topics", topic, "consume"]) => {
             let topic = topic.to_string();
-            consume::handle(event, &state.s3, &state.bucket, &topic).await
+            consume::handle(
+                event,
+                &state.s3,
+                &state.dynamo,
+                &state.offset_store,
+                &state.bucket,
+                &state.segment_index_table,
+                &topic,
+            )
+            .await
+        }
+
+        // POST /topics/{topic}/compact
+        (Method::POST, ["topics", topic, "compact"]) => {
+            let topic = topic.to_string();
+            compact::handle(
+                event,
+                &state.s3,
+                &state.dynamo,
+                &state.object_store,
+                &state.offset_store,
+                &state.bucket,
+                &state.segment_index_table,
+                &topic,
+            )
+            .await
+        }
+
+        // GET /topics/{topic}/consume-urls
+        (Method::GET, ["topics", topic, "consume-urls"]) => {
+            let topic = topic.to_string();
+            consume::handle_urls(event, &state.s3, &state.dynamo, &state.bucket, &state.segment_index_table, &topic)
+                .await
+        }
+
+        // POST /topics/{topic}/groups/{group}/commit
+        (Method::POST, ["topics", topic, "groups", group, "commit"]) => {
+            let topic = topic.to_string();
+            let group = group.to_string();
+            groups::handle_commit(event, &state.dynamo, &state.table, &topic, &group).await
+        }
+
+        // GET /topics/{topic}/groups/{group}/consume
+        (Method::GET, ["topics", topic, "groups", group, "consume"]) => {
+            let topic = topic.to_string();
+            let group = group.to_string();
+            groups::handle_consume(
+                event,
+                &state.s3,
+                &state.dynamo,
+                &state.bucket,
+                &state.table,
+                &state.segment_index_table,
+                &topic,
+                &group,
+            )
+            .await
         }
 
         // Health check
@@ -1,13 +1,31 @@
 use anyhow::Result;
-use aws_sdk_dynamodb::{types::AttributeValue, types::ReturnValue, Client as DynamoClient};
-use aws_sdk_s3::{primitives::ByteStream, Client as S3Client};
+use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoClient};
+use aws_sdk_s3::{presigning::PresigningConfig, Client as S3Client};
 use lambda_http::{Body, Error, Request, RequestPayloadExt, Response};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::storage::{ObjectStore, OffsetStore};
+
+/// How long a presigned produce/consume URL stays valid.
+const PRESIGNED_URL_TTL: Duration = Duration::from_secs(900);
+
+/// Outcome of attempting to claim a producer/sequence pair in the dedup table.
+enum SequenceClaim {
+    /// Nobody had claimed this (producer_id, sequence) before; it is now
+    /// recorded against the offset passed to `claim_sequence`.
+    New,
+    /// This sequence was already produced; carries the offset assigned the
+    /// first time so the retry can return it verbatim.
+    AlreadyProduced(i64),
+}
 
 #[derive(Deserialize)]
 struct ProduceRequest {
     payload: serde_json::Value,
+    producer_id: Option<String>,
+    sequence: Option<i64>,
 }
 
 #[derive(Serialize)]
@@ -23,13 +41,40 @@ struct Message {
     timestamp: u128,
 }
 
+#[derive(Deserialize)]
+struct BatchProduceRequest {
+    messages: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct BatchProduceResponse {
+    topic: String,
+    offsets: Vec<i64>,
+}
+
+#[derive(Serialize)]
+struct ProduceUrlResponse {
+    topic: String,
+    staging_key: String,
+    upload_url: String,
+    expires_in_seconds: u64,
+}
+
+#[derive(Deserialize)]
+struct CommitProduceUrlRequest {
+    staging_key: String,
+}
+
 /// Handle POST /topics/{topic}/produce
 pub async fn handle(
     event: Request,
     s3: &S3Client,
     dynamo: &DynamoClient,
+    offset_store: &Arc<dyn OffsetStore>,
     bucket: &str,
     table: &str,
+    segment_index_table: &str,
+    dedup_table: &str,
     topic: &str,
 ) -> Result<Response<Body>, Error> {
     // Parse request body
@@ -50,10 +95,40 @@ pub async fn handle(
         }
     };
 
-    // 1. Atomically get next offset from DynamoDB
-    let offset = next_offset(dynamo, table, topic).await?;
+    // If the producer supplied an idempotency key, look it up before doing
+    // any work so a retried request observes the original result instead of
+    // producing a duplicate message. The key is claimed after the append
+    // (step 3 below).
+    let dedup_key = match (&body.producer_id, body.sequence) {
+        (Some(producer_id), Some(sequence)) => Some((producer_id.clone(), sequence)),
+        _ => None,
+    };
+
+    if let Some((producer_id, sequence)) = &dedup_key {
+        if let Some(offset) = lookup_sequence_offset(dynamo, dedup_table, topic, producer_id, *sequence).await? {
+            tracing::info!(%topic, %producer_id, %sequence, %offset, "Duplicate produce request, returning existing offset");
+            let response = ProduceResponse {
+                topic: topic.to_string(),
+                offset,
+            };
+            return Ok(Response::builder()
+                .status(200)
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&response)?))?);
+        }
+    }
+
+    // 1. Atomically get next offset
+    let offset = offset_store.increment(topic, 1).await?;
 
-    // 2. Create message
+    // 2. Create message and durably append it to the topic's active segment
+    // *before* recording the dedup claim below. If this fails, the `?`
+    // propagates a 500 and no claim is ever written, so a retry sees no
+    // dedup entry and safely starts over. Claiming first (as an earlier
+    // version of this handler did) let a crash between the claim and the
+    // append leave behind a claim for a message that was never written —
+    // every retry would then get back a 200 and an offset for a message
+    // that doesn't exist anywhere, forever.
     let message = Message {
         offset,
         payload: body.payload,
@@ -62,26 +137,233 @@ pub async fn handle(
             .unwrap()
             .as_millis(),
     };
-
-    // 3. Write to S3
-    let s3_key = format!("topics/{}/{:020}.json", topic, offset);
     let message_bytes = serde_json::to_vec(&message)?;
+    crate::segments::append(s3, dynamo, table, segment_index_table, bucket, topic, offset, &message_bytes).await?;
+
+    tracing::info!(%topic, %offset, "Message produced");
 
-    s3.put_object()
+    // 3. Now that the message is durably stored, claim this (producer_id,
+    // sequence) pair for `offset` in a single conditional write that always
+    // carries the offset, so there's no window where a claimed pair has no
+    // recorded offset. If a concurrent retry already claimed the pair
+    // first, our message is still physically in the segment under `offset`
+    // (an accepted, harmless duplicate — the same kind of gap the offset
+    // space already tolerates), but we tell the caller about the winning
+    // offset instead of ours, so well-behaved callers converge on one.
+    if let Some((producer_id, sequence)) = &dedup_key {
+        match claim_sequence(dynamo, dedup_table, topic, producer_id, *sequence, offset).await? {
+            SequenceClaim::New => {}
+            SequenceClaim::AlreadyProduced(existing_offset) => {
+                tracing::info!(%topic, %producer_id, %sequence, offset = %existing_offset, "Lost race to claim sequence, reusing existing offset");
+                let response = ProduceResponse {
+                    topic: topic.to_string(),
+                    offset: existing_offset,
+                };
+                return Ok(Response::builder()
+                    .status(200)
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&response)?))?);
+            }
+        }
+    }
+
+    // 4. Return response
+    let response = ProduceResponse {
+        topic: topic.to_string(),
+        offset,
+    };
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+/// Handle POST /topics/{topic}/produce-batch
+pub async fn handle_batch(
+    event: Request,
+    s3: &S3Client,
+    dynamo: &DynamoClient,
+    offset_store: &Arc<dyn OffsetStore>,
+    bucket: &str,
+    table: &str,
+    segment_index_table: &str,
+    topic: &str,
+) -> Result<Response<Body>, Error> {
+    // Parse request body
+    let body: BatchProduceRequest = match event.payload() {
+        Ok(Some(b)) => b,
+        Ok(None) => {
+            return Ok(Response::builder()
+                .status(400)
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"error":"missing request body"}"#))?)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to parse request body");
+            return Ok(Response::builder()
+                .status(400)
+                .header("content-type", "application/json")
+                .body(Body::from(format!(r#"{{"error":"invalid json: {}"}}"#, e)))?)
+        }
+    };
+
+    if body.messages.is_empty() {
+        return Ok(Response::builder()
+            .status(400)
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"error":"messages must be non-empty"}"#))?);
+    }
+
+    let n = body.messages.len() as i64;
+
+    // 1. Reserve a contiguous block of offsets in a single atomic update
+    let last_offset = offset_store.increment(topic, n).await?;
+    let first_offset = last_offset - n + 1;
+    let offsets: Vec<i64> = (first_offset..=last_offset).collect();
+
+    // 2. Append each message to the topic's active segment, in offset order.
+    // Segment appends are a read-modify-write of one shared S3 object, so
+    // unlike the old per-key writes these can't be parallelized across a
+    // batch without racing each other; they're written sequentially.
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    for (payload, offset) in body.messages.into_iter().zip(offsets.iter().copied()) {
+        let message = Message {
+            offset,
+            payload,
+            timestamp,
+        };
+        let message_bytes = serde_json::to_vec(&message)?;
+        crate::segments::append(s3, dynamo, table, segment_index_table, bucket, topic, offset, &message_bytes)
+            .await?;
+    }
+
+    tracing::info!(%topic, %first_offset, %last_offset, count = %n, "Batch produced");
+
+    let response = BatchProduceResponse {
+        topic: topic.to_string(),
+        offsets,
+    };
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+/// Handle POST /topics/{topic}/produce-url
+///
+/// Hands back a presigned S3 URL the client can upload a large message body
+/// to directly, bypassing the Lambda/API Gateway body size limits. No
+/// offset is reserved here: the upload lands in a staging key outside the
+/// topic's segment log, and only takes a place in the log once the client
+/// confirms the upload via `/produce-url/commit`. That keeps every segment
+/// write going through `segments::append`, the same as every other produce
+/// path, instead of an S3 key the segment log never sees.
+pub async fn handle_produce_url(
+    s3: &S3Client,
+    bucket: &str,
+    topic: &str,
+) -> Result<Response<Body>, Error> {
+    let staging_key = format!("topics/{}/_staging/{}.json", topic, uuid::Uuid::new_v4());
+
+    let presigned = s3
+        .put_object()
         .bucket(bucket)
-        .key(&s3_key)
-        .body(ByteStream::from(message_bytes))
+        .key(&staging_key)
         .content_type("application/json")
-        .send()
+        .presigned(PresigningConfig::expires_in(PRESIGNED_URL_TTL)?)
         .await
         .map_err(|e| {
-            tracing::error!(error = %e, %s3_key, "Failed to write to S3");
+            tracing::error!(error = %e, %staging_key, "Failed to presign produce URL");
             e
         })?;
 
-    tracing::info!(%topic, %offset, %s3_key, "Message produced");
+    tracing::info!(%topic, %staging_key, "Issued presigned produce URL");
+
+    let response = ProduceUrlResponse {
+        topic: topic.to_string(),
+        staging_key,
+        upload_url: presigned.uri().to_string(),
+        expires_in_seconds: PRESIGNED_URL_TTL.as_secs(),
+    };
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+/// Handle POST /topics/{topic}/produce-url/commit
+///
+/// Reads back the payload the client uploaded to `staging_key`, allocates
+/// an offset, and appends it to the topic's active segment exactly like a
+/// normal produce. Cleans up the staging object once it's durably in the
+/// segment log.
+pub async fn handle_commit_produce_url(
+    event: Request,
+    s3: &S3Client,
+    dynamo: &DynamoClient,
+    object_store: &Arc<dyn ObjectStore>,
+    offset_store: &Arc<dyn OffsetStore>,
+    bucket: &str,
+    table: &str,
+    segment_index_table: &str,
+    topic: &str,
+) -> Result<Response<Body>, Error> {
+    let body: CommitProduceUrlRequest = match event.payload() {
+        Ok(Some(b)) => b,
+        Ok(None) => {
+            return Ok(Response::builder()
+                .status(400)
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"error":"missing request body"}"#))?)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to parse request body");
+            return Ok(Response::builder()
+                .status(400)
+                .header("content-type", "application/json")
+                .body(Body::from(format!(r#"{{"error":"invalid json: {}"}}"#, e)))?)
+        }
+    };
+
+    let staged = match object_store.get(&body.staging_key).await? {
+        Some(bytes) => bytes,
+        None => {
+            return Ok(Response::builder()
+                .status(404)
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"error":"staged upload not found or already committed"}"#))?)
+        }
+    };
+
+    let payload: serde_json::Value = serde_json::from_slice(&staged).map_err(|e| {
+        tracing::error!(error = %e, staging_key = %body.staging_key, "Staged upload is not valid JSON");
+        e
+    })?;
+
+    let offset = offset_store.increment(topic, 1).await?;
+    let message = Message {
+        offset,
+        payload,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis(),
+    };
+
+    let message_bytes = serde_json::to_vec(&message)?;
+    crate::segments::append(s3, dynamo, table, segment_index_table, bucket, topic, offset, &message_bytes).await?;
+
+    object_store.delete(&body.staging_key).await?;
+
+    tracing::info!(%topic, %offset, staging_key = %body.staging_key, "Committed staged produce-url upload");
 
-    // 4. Return response
     let response = ProduceResponse {
         topic: topic.to_string(),
         offset,
@@ -93,30 +375,77 @@ pub async fn handle(
         .body(Body::from(serde_json::to_string(&response)?))?)
 }
 
-/// Atomically increment topic offset and return the new value
-async fn next_offset(client: &DynamoClient, table: &str, topic: &str) -> Result<i64, Error> {
+/// Claim a (producer_id, sequence) pair in the dedup table for `offset` via
+/// a single conditional put, keyed `pk = topic#producer_id`, `sk =
+/// sequence`. The offset is written atomically with the claim itself (never
+/// in a separate follow-up write), so there is no intermediate state where
+/// a pair is claimed but has no recorded offset for a retry to observe.
+/// Succeeds only the first time it is called for a given pair; a retry
+/// losing the race gets back the offset the winning call claimed.
+async fn claim_sequence(
+    client: &DynamoClient,
+    dedup_table: &str,
+    topic: &str,
+    producer_id: &str,
+    sequence: i64,
+    offset: i64,
+) -> Result<SequenceClaim, Error> {
+    let pk = format!("{}#{}", topic, producer_id);
+
+    let result = client
+        .put_item()
+        .table_name(dedup_table)
+        .item("pk", AttributeValue::S(pk.clone()))
+        .item("sk", AttributeValue::N(sequence.to_string()))
+        .item("offset", AttributeValue::N(offset.to_string()))
+        .condition_expression("attribute_not_exists(sk)")
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(SequenceClaim::New),
+        Err(e) => {
+            if e.as_service_error()
+                .map(|se| se.is_conditional_check_failed_exception())
+                .unwrap_or(false)
+            {
+                let existing_offset = lookup_sequence_offset(client, dedup_table, topic, producer_id, sequence)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("dedup entry for {}#{} disappeared after claim race", pk, sequence))?;
+                Ok(SequenceClaim::AlreadyProduced(existing_offset))
+            } else {
+                tracing::error!(error = %e, %topic, %producer_id, %sequence, "Failed to claim producer sequence");
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Read back the offset claimed for a (topic, producer_id, sequence) pair,
+/// if any. Every claim writes its offset atomically (see `claim_sequence`),
+/// so an existing entry always has one.
+async fn lookup_sequence_offset(
+    client: &DynamoClient,
+    dedup_table: &str,
+    topic: &str,
+    producer_id: &str,
+    sequence: i64,
+) -> Result<Option<i64>, Error> {
+    let pk = format!("{}#{}", topic, producer_id);
+
     let result = client
-        .update_item()
-        .table_name(table)
-        .key("pk", AttributeValue::S(topic.to_string()))
-        .update_expression("SET current_offset = if_not_exists(current_offset, :zero) + :inc")
-        .expression_attribute_values(":zero", AttributeValue::N("0".into()))
-        .expression_attribute_values(":inc", AttributeValue::N("1".into()))
-        .return_values(ReturnValue::UpdatedNew)
+        .get_item()
+        .table_name(dedup_table)
+        .key("pk", AttributeValue::S(pk.clone()))
+        .key("sk", AttributeValue::N(sequence.to_string()))
+        .consistent_read(true)
         .send()
         .await
         .map_err(|e| {
-            tracing::error!(error = %e, %topic, "Failed to increment offset in DynamoDB");
+            tracing::error!(error = %e, %pk, %sequence, "Failed to read back dedup entry");
             e
         })?;
 
-    let offset = result
-        .attributes()
-        .and_then(|attrs| attrs.get("current_offset"))
-        .and_then(|v| v.as_n().ok())
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(1);
-
-    Ok(offset)
+    Ok(result.item().and_then(|item| item.get("offset")).and_then(|v| v.as_n().ok()).and_then(|n| n.parse().ok()))
 }
 
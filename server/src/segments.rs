@@ -0,0 +1,348 @@
+use anyhow::Result;
+use aws_sdk_dynamodb::{types::AttributeValue, types::ReturnValue, Client as DynamoClient};
+use aws_sdk_s3::{primitives::ByteStream, Client as S3Client};
+use lambda_http::Error;
+
+/// Segments are sealed once they span this many offsets (counted from the
+/// base offset, so gaps in the offset space count too)...
+const SEAL_RECORD_COUNT: i64 = 1_000;
+/// ...or once they reach this many bytes, whichever comes first.
+const SEAL_SIZE_BYTES: usize = 4 * 1024 * 1024;
+/// How many attempts (the first one included) `append` makes at its
+/// read-modify-write of the active segment, retrying after each lost race
+/// to a concurrent appender, before giving up.
+const MAX_APPEND_RETRIES: u32 = 10;
+
+pub(crate) fn segment_key(topic: &str, base_offset: i64) -> String {
+    format!("topics/{}/seg-{:020}.log", topic, base_offset)
+}
+
+/// Append one record to the topic's active segment, rolling to a new
+/// segment once the current one crosses the seal threshold. S3 objects are
+/// immutable, so "append" means read-modify-write the whole segment object.
+///
+/// Each record is framed as `[offset: i64 BE][len: u32 BE][bytes]`. The
+/// offset is stored explicitly rather than inferred from record position,
+/// because not every offset the shared counter allocates is guaranteed to
+/// land in this segment exactly once, in order (e.g. a crashed retry can
+/// burn an offset that's never written). Framing records with their own
+/// offset keeps `read_range` correct even when the allocated offset space
+/// has gaps.
+pub(crate) async fn append(
+    s3: &S3Client,
+    dynamo: &DynamoClient,
+    table: &str,
+    index_table: &str,
+    bucket: &str,
+    topic: &str,
+    offset: i64,
+    record: &[u8],
+) -> Result<(), Error> {
+    let base_offset = active_segment_base(dynamo, table, topic, offset).await?;
+    let key = segment_key(topic, base_offset);
+
+    // The read-modify-write below races against any other invocation
+    // appending to the same active segment concurrently. Guard the write
+    // with the ETag of whatever we read (or `if_none_match("*")` when the
+    // segment doesn't exist yet), so a racing writer that lands first makes
+    // ours fail instead of silently clobbering it; retry against the fresh
+    // object on that failure.
+    for attempt in 0..MAX_APPEND_RETRIES {
+        let (mut body, etag) = match s3.get_object().bucket(bucket).key(&key).send().await {
+            Ok(existing) => {
+                let etag = existing.e_tag().map(|s| s.to_string());
+                (existing.body.collect().await?.into_bytes().to_vec(), etag)
+            }
+            Err(e) => {
+                if e.as_service_error().map(|se| se.is_no_such_key()).unwrap_or(false) {
+                    (Vec::new(), None)
+                } else {
+                    tracing::error!(error = %e, %key, "Failed to read active segment");
+                    return Err(e.into());
+                }
+            }
+        };
+
+        body.extend_from_slice(&offset.to_be_bytes());
+        body.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        body.extend_from_slice(record);
+        let size_bytes = body.len();
+
+        let mut put = s3
+            .put_object()
+            .bucket(bucket)
+            .key(&key)
+            .body(ByteStream::from(body))
+            .content_type("application/octet-stream");
+        put = match &etag {
+            Some(etag) => put.if_match(etag),
+            None => put.if_none_match("*"),
+        };
+
+        match put.send().await {
+            Ok(_) => {
+                index_segment(dynamo, index_table, topic, base_offset, offset).await?;
+
+                if size_bytes >= SEAL_SIZE_BYTES || offset - base_offset + 1 >= SEAL_RECORD_COUNT {
+                    seal_segment(dynamo, table, topic, base_offset, offset).await?;
+                }
+
+                tracing::info!(%topic, %offset, %base_offset, %size_bytes, "Appended record to segment");
+
+                return Ok(());
+            }
+            Err(e) => {
+                let lost_race = e.raw_response().map(|r| r.status().as_u16() == 412).unwrap_or(false);
+                if lost_race {
+                    tracing::info!(%topic, %key, %attempt, "Lost race writing active segment, retrying");
+                    continue;
+                }
+                tracing::error!(error = %e, %key, "Failed to write active segment");
+                return Err(e.into());
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!("exhausted retries appending to active segment {}", key).into())
+}
+
+/// Get (or lazily create) the base offset of the topic's current active
+/// segment.
+async fn active_segment_base(
+    client: &DynamoClient,
+    table: &str,
+    topic: &str,
+    offset: i64,
+) -> Result<i64, Error> {
+    let pk = format!("{}#active-segment", topic);
+
+    let result = client
+        .update_item()
+        .table_name(table)
+        .key("pk", AttributeValue::S(pk))
+        .update_expression("SET base_offset = if_not_exists(base_offset, :offset)")
+        .expression_attribute_values(":offset", AttributeValue::N(offset.to_string()))
+        .return_values(ReturnValue::UpdatedNew)
+        .send()
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, %topic, "Failed to read active segment pointer");
+            e
+        })?;
+
+    let base_offset = result
+        .attributes()
+        .and_then(|attrs| attrs.get("base_offset"))
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(offset);
+
+    Ok(base_offset)
+}
+
+/// Record (or refresh) the sparse index entry for a segment: the highest
+/// offset known to be in it so far. Resolving an offset to a segment is
+/// then a single query for the largest indexed base offset <= the target.
+async fn index_segment(
+    client: &DynamoClient,
+    index_table: &str,
+    topic: &str,
+    base_offset: i64,
+    last_offset: i64,
+) -> Result<(), Error> {
+    let result = client
+        .update_item()
+        .table_name(index_table)
+        .key("pk", AttributeValue::S(format!("{}#segments", topic)))
+        .key("sk", AttributeValue::N(base_offset.to_string()))
+        .update_expression("SET last_offset = :last")
+        .condition_expression("attribute_not_exists(last_offset) OR last_offset < :last")
+        .expression_attribute_values(":last", AttributeValue::N(last_offset.to_string()))
+        .send()
+        .await;
+
+    if let Err(e) = result {
+        if e.as_service_error()
+            .map(|se| se.is_conditional_check_failed_exception())
+            .unwrap_or(false)
+        {
+            // A higher offset is already recorded for this segment.
+            return Ok(());
+        }
+        tracing::error!(error = %e, %topic, %base_offset, "Failed to update segment index");
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Roll the active segment pointer to a fresh segment starting right after
+/// `last_offset`, sealing the one that just filled up. The update is
+/// conditioned on the old base offset so two invocations racing to seal the
+/// same segment don't roll it twice.
+async fn seal_segment(
+    client: &DynamoClient,
+    table: &str,
+    topic: &str,
+    base_offset: i64,
+    last_offset: i64,
+) -> Result<(), Error> {
+    let pk = format!("{}#active-segment", topic);
+    let new_base = last_offset + 1;
+
+    let result = client
+        .update_item()
+        .table_name(table)
+        .key("pk", AttributeValue::S(pk))
+        .update_expression("SET base_offset = :new")
+        .condition_expression("base_offset = :old")
+        .expression_attribute_values(":old", AttributeValue::N(base_offset.to_string()))
+        .expression_attribute_values(":new", AttributeValue::N(new_base.to_string()))
+        .send()
+        .await;
+
+    if let Err(e) = result {
+        if e.as_service_error()
+            .map(|se| se.is_conditional_check_failed_exception())
+            .unwrap_or(false)
+        {
+            // Another invocation already sealed this segment.
+            return Ok(());
+        }
+        tracing::error!(error = %e, %topic, %base_offset, "Failed to seal segment");
+        return Err(e.into());
+    }
+
+    tracing::info!(%topic, %base_offset, %new_base, "Sealed segment");
+    Ok(())
+}
+
+/// Read up to `limit` records starting at `start_offset`, resolving the
+/// segment(s) that cover the range via the sparse index and following the
+/// segment chain forward as needed.
+pub(crate) async fn read_range(
+    s3: &S3Client,
+    dynamo: &DynamoClient,
+    index_table: &str,
+    bucket: &str,
+    topic: &str,
+    start_offset: i64,
+    limit: i32,
+) -> Result<Vec<(i64, Vec<u8>)>, Error> {
+    let mut records = Vec::new();
+    let mut cursor = start_offset;
+
+    while records.len() < limit as usize {
+        let Some((base_offset, last_offset)) = resolve_segment(dynamo, index_table, topic, cursor).await?
+        else {
+            break;
+        };
+
+        if last_offset < cursor {
+            // Segment hasn't grown since we indexed it; nothing more to read.
+            break;
+        }
+
+        let key = segment_key(topic, base_offset);
+        let body = match s3.get_object().bucket(bucket).key(&key).send().await {
+            Ok(result) => result.body.collect().await?.into_bytes(),
+            Err(e) => {
+                if e.as_service_error().map(|se| se.is_no_such_key()).unwrap_or(false) {
+                    break;
+                }
+                tracing::error!(error = %e, %key, "Failed to read segment");
+                return Err(e.into());
+            }
+        };
+
+        let mut pos = 0usize;
+        while pos + 12 <= body.len() && records.len() < limit as usize {
+            let record_offset = i64::from_be_bytes(body[pos..pos + 8].try_into().unwrap());
+            pos += 8;
+            let len = u32::from_be_bytes(body[pos..pos + 4].try_into().unwrap()) as usize;
+            pos += 4;
+            if pos + len > body.len() {
+                break;
+            }
+            if record_offset >= cursor {
+                records.push((record_offset, body[pos..pos + len].to_vec()));
+            }
+            pos += len;
+        }
+
+        cursor = last_offset + 1;
+    }
+
+    Ok(records)
+}
+
+/// Resolve up to `limit` segments covering `start_offset` onward, without
+/// reading any segment bodies. Used by callers that hand back whole-segment
+/// references (e.g. presigned download URLs) rather than individual
+/// records, since a segment holds many messages and there's no per-message
+/// S3 object to point at anymore.
+pub(crate) async fn resolve_segments_in_range(
+    dynamo: &DynamoClient,
+    index_table: &str,
+    topic: &str,
+    start_offset: i64,
+    limit: i32,
+) -> Result<Vec<(i64, i64)>, Error> {
+    let mut segments = Vec::new();
+    let mut cursor = start_offset;
+
+    while segments.len() < limit as usize {
+        let Some((base_offset, last_offset)) = resolve_segment(dynamo, index_table, topic, cursor).await? else {
+            break;
+        };
+
+        if last_offset < cursor {
+            break;
+        }
+
+        segments.push((base_offset, last_offset));
+        cursor = last_offset + 1;
+    }
+
+    Ok(segments)
+}
+
+/// Find the segment whose base offset is the largest one at or before
+/// `offset`, along with the highest offset known to be stored in it.
+async fn resolve_segment(
+    client: &DynamoClient,
+    index_table: &str,
+    topic: &str,
+    offset: i64,
+) -> Result<Option<(i64, i64)>, Error> {
+    let result = client
+        .query()
+        .table_name(index_table)
+        .key_condition_expression("pk = :pk AND sk <= :offset")
+        .expression_attribute_values(":pk", AttributeValue::S(format!("{}#segments", topic)))
+        .expression_attribute_values(":offset", AttributeValue::N(offset.to_string()))
+        .scan_index_forward(false)
+        .limit(1)
+        .send()
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, %topic, %offset, "Failed to resolve segment for offset");
+            e
+        })?;
+
+    let item = match result.items().first() {
+        Some(item) => item,
+        None => return Ok(None),
+    };
+
+    let base_offset = item.get("sk").and_then(|v| v.as_n().ok()).and_then(|n| n.parse().ok());
+    let last_offset = item
+        .get("last_offset")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok());
+
+    match (base_offset, last_offset) {
+        (Some(base_offset), Some(last_offset)) => Ok(Some((base_offset, last_offset))),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,349 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{types::AttributeValue, types::ReturnValue, Client as DynamoClient};
+use aws_sdk_s3::{primitives::ByteStream, Client as S3Client};
+use lambda_http::Error;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Generic object storage: put/get/list/delete over opaque byte blobs keyed
+/// by path-like strings, backed by S3 in production or `LocalObjectStore`
+/// for local/offline use. This only covers call sites that do simple
+/// whole-object reads/writes with no concurrency guard — currently the
+/// produce-url staging/commit flow and Parquet compaction output.
+///
+/// `segments` (and the DynamoDB side of `groups`) deliberately stay on raw
+/// `S3Client`/`DynamoClient` instead of this trait: they need an ETag-
+/// conditional S3 put (see `segments::append`) and DynamoDB conditional
+/// updates / sparse-index queries (see `segments::{active_segment_base,
+/// index_segment, seal_segment, resolve_segment}` and
+/// `produce::claim_sequence`), none of which this trait models. Widening it
+/// to cover those would mean designing a second, much larger trait for
+/// conditional KV/index storage — out of scope here. Net effect:
+/// `STORAGE_BACKEND=local` moves only the staging objects, the Parquet
+/// output and the topic offset counter off AWS. No handler runs fully
+/// offline: produce-url commit and compaction still read and write the
+/// segment log and its index through real S3 and DynamoDB, as every other
+/// handler does.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error>;
+    /// Returns `Ok(None)` if the key doesn't exist.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+    /// List keys under `prefix`, optionally resuming after `start_after`,
+    /// capped at `max` results.
+    async fn list(&self, prefix: &str, start_after: Option<&str>, max: i32) -> Result<Vec<String>, Error>;
+    /// Delete a key. A no-op if it doesn't exist.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+}
+
+/// A generic atomically-incrementing counter, used to allocate topic
+/// offsets.
+#[async_trait]
+pub trait OffsetStore: Send + Sync {
+    /// Atomically add `n` to the counter for `key` and return its new value.
+    async fn increment(&self, key: &str, n: i64) -> Result<i64, Error>;
+    /// Read the counter for `key` without modifying it (0 if unset).
+    async fn read(&self, key: &str) -> Result<i64, Error>;
+}
+
+/// S3-backed `ObjectStore`.
+pub struct S3ObjectStore {
+    client: S3Client,
+    bucket: String,
+}
+
+impl S3ObjectStore {
+    pub fn new(client: S3Client, bucket: String) -> Self {
+        Self { client, bucket }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(bytes))
+            .content_type("application/json")
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, %key, "Failed to put object to S3");
+                e
+            })?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.client.get_object().bucket(&self.bucket).key(key).send().await {
+            Ok(result) => Ok(Some(result.body.collect().await?.into_bytes().to_vec())),
+            Err(e) => {
+                if e.as_service_error().map(|se| se.is_no_such_key()).unwrap_or(false) {
+                    Ok(None)
+                } else {
+                    tracing::error!(error = %e, %key, "Failed to get object from S3");
+                    Err(e.into())
+                }
+            }
+        }
+    }
+
+    async fn list(&self, prefix: &str, start_after: Option<&str>, max: i32) -> Result<Vec<String>, Error> {
+        let mut req = self.client.list_objects_v2().bucket(&self.bucket).prefix(prefix).max_keys(max);
+        if let Some(start_after) = start_after {
+            req = req.start_after(start_after);
+        }
+
+        let result = req.send().await.map_err(|e| {
+            tracing::error!(error = %e, %prefix, "Failed to list objects from S3");
+            e
+        })?;
+
+        Ok(result.contents().iter().filter_map(|obj| obj.key().map(String::from)).collect())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.client.delete_object().bucket(&self.bucket).key(key).send().await.map_err(|e| {
+            tracing::error!(error = %e, %key, "Failed to delete object from S3");
+            e
+        })?;
+        Ok(())
+    }
+}
+
+/// DynamoDB-backed `OffsetStore`, one item per key with a `current_offset`
+/// attribute incremented via `SET ... = if_not_exists(...) + :n`.
+pub struct DynamoOffsetStore {
+    client: DynamoClient,
+    table: String,
+}
+
+impl DynamoOffsetStore {
+    pub fn new(client: DynamoClient, table: String) -> Self {
+        Self { client, table }
+    }
+}
+
+#[async_trait]
+impl OffsetStore for DynamoOffsetStore {
+    async fn increment(&self, key: &str, n: i64) -> Result<i64, Error> {
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .key("pk", AttributeValue::S(key.to_string()))
+            .update_expression("SET current_offset = if_not_exists(current_offset, :zero) + :n")
+            .expression_attribute_values(":zero", AttributeValue::N("0".into()))
+            .expression_attribute_values(":n", AttributeValue::N(n.to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, %key, %n, "Failed to increment offset in DynamoDB");
+                e
+            })?;
+
+        Ok(result
+            .attributes()
+            .and_then(|attrs| attrs.get("current_offset"))
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(n))
+    }
+
+    async fn read(&self, key: &str) -> Result<i64, Error> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key("pk", AttributeValue::S(key.to_string()))
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, %key, "Failed to read offset from DynamoDB");
+                e
+            })?;
+
+        Ok(result
+            .item()
+            .and_then(|item| item.get("current_offset"))
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(0))
+    }
+}
+
+/// Filesystem-backed `ObjectStore`, for keeping the produce-url staging
+/// objects and compaction output on local disk (e.g. in tests) instead of
+/// S3. Does not help with `segments`/`groups`, which those same flows still
+/// use; see the scope note on the `ObjectStore` trait above.
+pub struct LocalObjectStore {
+    base_dir: PathBuf,
+}
+
+impl LocalObjectStore {
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            base_dir: base_dir.into(),
+        }
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.base_dir.join(key)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalObjectStore {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+        let path = self.path_for(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, bytes).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match tokio::fs::read(self.path_for(key)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str, start_after: Option<&str>, max: i32) -> Result<Vec<String>, Error> {
+        let dir = self.path_for(prefix);
+
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str() {
+                keys.push(format!("{}{}", prefix, name));
+            }
+        }
+
+        keys.sort();
+        if let Some(start_after) = start_after {
+            keys.retain(|k| k.as_str() > start_after);
+        }
+        keys.truncate(max.max(0) as usize);
+
+        Ok(keys)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.path_for(key)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// In-memory `OffsetStore` pairing with `LocalObjectStore` in tests; not
+/// durable across process restarts.
+#[derive(Default)]
+pub struct LocalOffsetStore {
+    counters: Mutex<HashMap<String, i64>>,
+}
+
+#[async_trait]
+impl OffsetStore for LocalOffsetStore {
+    async fn increment(&self, key: &str, n: i64) -> Result<i64, Error> {
+        let mut counters = self.counters.lock().await;
+        let value = counters.entry(key.to_string()).or_insert(0);
+        *value += n;
+        Ok(*value)
+    }
+
+    async fn read(&self, key: &str) -> Result<i64, Error> {
+        let counters = self.counters.lock().await;
+        Ok(counters.get(key).copied().unwrap_or(0))
+    }
+}
+
+/// Build the object/offset store backends from `STORAGE_BACKEND` (`s3` by
+/// default, or `local` for a filesystem-backed setup used in tests).
+pub fn build_backends(
+    s3: S3Client,
+    dynamo: DynamoClient,
+    bucket: &str,
+    table: &str,
+) -> (Arc<dyn ObjectStore>, Arc<dyn OffsetStore>) {
+    match std::env::var("STORAGE_BACKEND").as_deref() {
+        Ok("local") => {
+            let base_dir =
+                std::env::var("LOCAL_STORAGE_DIR").unwrap_or_else(|_| "/tmp/serverless-streams".to_string());
+            (
+                Arc::new(LocalObjectStore::new(base_dir)) as Arc<dyn ObjectStore>,
+                Arc::new(LocalOffsetStore::default()) as Arc<dyn OffsetStore>,
+            )
+        }
+        _ => (
+            Arc::new(S3ObjectStore::new(s3, bucket.to_string())) as Arc<dyn ObjectStore>,
+            Arc::new(DynamoOffsetStore::new(dynamo, table.to_string())) as Arc<dyn OffsetStore>,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("serverless-streams-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn local_object_store_round_trips_put_get_delete() {
+        let store = LocalObjectStore::new(temp_dir());
+        let key = "topics/demo/seg-00000000000000000000.log";
+
+        assert_eq!(store.get(key).await.unwrap(), None);
+
+        store.put(key, b"hello".to_vec()).await.unwrap();
+        assert_eq!(store.get(key).await.unwrap(), Some(b"hello".to_vec()));
+
+        store.delete(key).await.unwrap();
+        assert_eq!(store.get(key).await.unwrap(), None);
+
+        // Deleting an already-missing key is a no-op, not an error.
+        store.delete(key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_object_store_lists_keys_under_prefix_sorted_and_paginated() {
+        let store = LocalObjectStore::new(temp_dir());
+
+        for name in ["b.json", "a.json", "c.json"] {
+            store.put(&format!("topics/demo/{}", name), Vec::new()).await.unwrap();
+        }
+
+        let keys = store.list("topics/demo/", None, 10).await.unwrap();
+        assert_eq!(keys, vec!["topics/demo/a.json", "topics/demo/b.json", "topics/demo/c.json"]);
+
+        let page = store.list("topics/demo/", Some("topics/demo/a.json"), 1).await.unwrap();
+        assert_eq!(page, vec!["topics/demo/b.json"]);
+    }
+
+    #[tokio::test]
+    async fn local_offset_store_increments_atomically_per_key() {
+        let store = LocalOffsetStore::default();
+
+        assert_eq!(store.read("topic-a").await.unwrap(), 0);
+        assert_eq!(store.increment("topic-a", 1).await.unwrap(), 1);
+        assert_eq!(store.increment("topic-a", 5).await.unwrap(), 6);
+        assert_eq!(store.read("topic-b").await.unwrap(), 0);
+    }
+}